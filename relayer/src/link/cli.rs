@@ -1,10 +1,14 @@
 use std::convert::TryInto;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::time::{Duration, Instant};
 
-use tracing::{error, error_span, info};
+use tracing::{error_span, info};
 
+use ibc::core::ics04_channel::events::TimeoutPacket;
+use ibc::core::ics04_channel::packet::Packet;
 use ibc::events::IbcEvent;
+use ibc::timestamp::Timestamp;
 use ibc::Height;
 
 use crate::chain::counterparty::{unreceived_acknowledgements, unreceived_packets};
@@ -13,34 +17,56 @@ use crate::link::error::LinkError;
 use crate::link::operational_data::OperationalData;
 use crate::link::packet_events::{query_packet_events_with, query_send_packet_events};
 use crate::link::relay_path::RelayPath;
-use crate::link::Link;
 use crate::link::relay_sender::SyncSender;
+use crate::link::Link;
+
+/// The granularity at which [`wait_for_conn_delay`] re-checks the remaining delay and the
+/// cancellation token, instead of sleeping for the full estimated duration in one shot. Keeping
+/// this small lets the wait self-correct if blocks arrive faster or slower than
+/// `max_expected_time_per_block` predicted, and lets a cancellation be noticed promptly.
+const WAIT_TICK: Duration = Duration::from_millis(500);
 
-// TODO(Adi): Open an issue or discussion. Options are:
-//  a. We remove this code and deprecate relaying on paths with non-zero delay.
-//  b. Maintain support for interactive relaying on non-zeroy delay paths.
+/// How many [`WAIT_TICK`]s to let pass between "still waiting" log lines in
+/// [`wait_for_conn_delay`]. Connection delays are commonly minutes to hours, so logging on
+/// every tick would flood the log; this logs on the first tick and roughly every 30s after.
+const WAIT_LOG_EVERY_N_TICKS: u32 = 60;
+
+/// Fetches an operational data that has fulfilled its predefined delay period. May _block_
+/// waiting for the delay period to pass. This is the blocking primitive a dedicated,
+/// per-path worker thread (as used by the passive relaying mode, `hermes start`) can drive,
+/// where blocking that thread until the datum is ready is acceptable. Nothing in this module
+/// calls it; a caller passes the `cancel` token that can abort the wait.
+///
+/// Interactive commands (`packet-recv`, `packet-ack`) must never hang the calling thread like
+/// this; see [`Link::drain_ready_operational_data`] for the non-blocking counterpart they use.
 #[allow(dead_code)]
 impl<ChainA: ChainHandle, ChainB: ChainHandle> RelayPath<ChainA, ChainB> {
     /// Fetches an operational data that has fulfilled its predefined delay period. May _block_
     /// waiting for the delay period to pass.
-    /// Returns `Ok(None)` if there is no operational data scheduled.
+    ///
+    /// Returns `Ok(None)` if there is no operational data scheduled, or if `cancel` is set while
+    /// waiting for the delay to pass (e.g. on a Ctrl-C or a supervisor-issued stop), so that the
+    /// wait can be aborted cleanly instead of leaving a zombie sleeping thread.
     pub(crate) fn fetch_scheduled_operational_data(
         &self,
+        cancel: &AtomicBool,
     ) -> Result<Option<OperationalData>, LinkError> {
         if let Some(odata) = self.src_operational_data.pop_front() {
-            Ok(Some(wait_for_conn_delay(
+            wait_for_conn_delay(
                 odata,
+                cancel,
                 &|| self.src_time_latest(),
                 &|| self.src_max_block_time(),
                 &|| self.src_latest_height(),
-            )?))
+            )
         } else if let Some(odata) = self.dst_operational_data.pop_front() {
-            Ok(Some(wait_for_conn_delay(
+            wait_for_conn_delay(
                 odata,
+                cancel,
                 &|| self.dst_time_latest(),
                 &|| self.dst_max_block_time(),
                 &|| self.dst_latest_height(),
-            )?))
+            )
         } else {
             Ok(None)
         }
@@ -48,8 +74,17 @@ impl<ChainA: ChainHandle, ChainB: ChainHandle> RelayPath<ChainA, ChainB> {
 }
 
 impl<ChainA: ChainHandle, ChainB: ChainHandle> Link<ChainA, ChainB> {
-    /// Implements the `packet-recv` CLI
-    pub fn relay_recv_packet_and_timeout_messages(&self) -> Result<Vec<IbcEvent>, LinkError> {
+    /// Implements the `packet-recv` CLI.
+    ///
+    /// Relays a `MsgRecvPacket` for every unreceived packet that is still within its timeout
+    /// window, and a `MsgTimeout`/`MsgTimeoutOnClose` back to the source chain for every one
+    /// whose `timeout_height`/`timeout_timestamp` has already elapsed on the destination.
+    /// Only data whose connection delay has already passed is relayed before this returns;
+    /// the rest stays queued (see [`Link::drain_ready_operational_data`]).
+    pub fn relay_recv_packet_and_timeout_messages(
+        &self,
+        cancel: &AtomicBool,
+    ) -> Result<Vec<IbcEvent>, LinkError> {
         let _span = error_span!(
             "PacketRecvCmd",
             src_chain = %self.a_to_b.src_chain().id(),
@@ -59,16 +94,6 @@ impl<ChainA: ChainHandle, ChainB: ChainHandle> Link<ChainA, ChainB> {
         )
         .entered();
 
-        // Relaying on a non-zero connection delay requires (indefinite) blocking
-        // to wait for the connection delay to pass.
-        // We do not support this in interactive mode.
-        if !self.a_to_b.channel().connection_delay.is_zero() {
-            error!(
-                "relaying on a non-zero connection delay path is not supported in interactive mode"
-            );
-            panic!("please use the passive relaying mode (`hermes start`)");
-        }
-
         // Find the sequence numbers of unreceived packets
         let (sequences, src_response_height) = unreceived_packets(
             self.a_to_b.dst_chain(),
@@ -83,8 +108,9 @@ impl<ChainA: ChainHandle, ChainB: ChainHandle> Link<ChainA, ChainB> {
 
         info!("unreceived packets found: {} ", sequences.len());
 
-        // Relay
-        let mut results = vec![];
+        // Queue up the send events as operational data rather than relaying them
+        // synchronously: on a non-zero connection-delay path the data may not be
+        // ready to submit yet, and we must not block this thread waiting for it.
         for events_chunk in query_packet_events_with(
             &sequences,
             src_response_height,
@@ -92,15 +118,32 @@ impl<ChainA: ChainHandle, ChainB: ChainHandle> Link<ChainA, ChainB> {
             &self.a_to_b.path_id,
             query_send_packet_events,
         ) {
-            let mut last_events = self.a_to_b.relay_from_events(events_chunk)?;
-            results.append(&mut last_events.events);
+            // A send event whose packet has already timed out on the destination can no
+            // longer be relayed as a `MsgRecvPacket`: split those out and route them back
+            // to the source chain as `MsgTimeout`/`MsgTimeoutOnClose`s instead.
+            let (recv_events, timeout_events) = self.partition_timed_out_events(events_chunk)?;
+
+            if !recv_events.is_empty() {
+                self.a_to_b.events_to_operational_data(recv_events)?;
+            }
+
+            if !timeout_events.is_empty() {
+                info!(
+                    "packets timed out on destination, relaying timeouts back to source: {}",
+                    timeout_events.len()
+                );
+                self.a_to_b.events_to_operational_data(timeout_events)?;
+            }
         }
 
-        Ok(results)
+        self.drain_ready_operational_data(cancel)
     }
 
     /// Implements the `packet-ack` CLI
-    pub fn relay_ack_packet_messages(&self) -> Result<Vec<IbcEvent>, LinkError> {
+    pub fn relay_ack_packet_messages(
+        &self,
+        cancel: &AtomicBool,
+    ) -> Result<Vec<IbcEvent>, LinkError> {
         let _span = error_span!(
             "PacketAckCmd",
             src_chain = %self.a_to_b.src_chain().id(),
@@ -110,16 +153,6 @@ impl<ChainA: ChainHandle, ChainB: ChainHandle> Link<ChainA, ChainB> {
         )
         .entered();
 
-        // Relaying on a non-zero connection delay requires (indefinite) blocking
-        // to wait for the connection delay to pass.
-        // We do not support this in interactive mode.
-        if !self.a_to_b.channel().connection_delay.is_zero() {
-            error!(
-                "relaying on a non-zero connection delay path is not supported in interactive mode"
-            );
-            panic!("please use the passive relaying mode (`hermes start`)");
-        }
-
         // Find the sequence numbers of unreceived acknowledgements
         let (sequences, src_response_height) = unreceived_acknowledgements(
             self.a_to_b.dst_chain(),
@@ -134,8 +167,6 @@ impl<ChainA: ChainHandle, ChainB: ChainHandle> Link<ChainA, ChainB> {
 
         info!("unreceived acknowledgements found: {} ", sequences.len());
 
-        // Relay
-        let mut results = vec![];
         for events_chunk in query_packet_events_with(
             &sequences,
             src_response_height,
@@ -143,93 +174,230 @@ impl<ChainA: ChainHandle, ChainB: ChainHandle> Link<ChainA, ChainB> {
             &self.a_to_b.path_id,
             query_send_packet_events,
         ) {
-            // Bypass scheduling and waiting on operational data, relay directly.
             self.a_to_b.events_to_operational_data(events_chunk)?;
+        }
 
-            let (src_ods, dst_ods) =
-                self.a_to_b.try_fetch_scheduled_operational_data()?;
+        self.drain_ready_operational_data(cancel)
+    }
 
-            for od in dst_ods {
-                let mut reply =
-                    self.relay_from_operational_data::<SyncSender>(od.clone())?;
+    /// Relays every operational datum that is currently ready on either direction of the
+    /// path, without blocking on data that is still subject to its connection delay.
+    ///
+    /// This drives the event-driven counterpart to [`RelayPath::fetch_scheduled_operational_data`]:
+    /// each iteration polls [`RelayPath::try_fetch_scheduled_operational_data`], which hands back
+    /// whatever has become ready -- on either direction of `a_to_b`, including any timeout
+    /// messages queued back onto its `src_operational_data` -- and re-queues the rest for a
+    /// later poll; what it hands back is then relayed here. Once a pass yields nothing new, or `cancel` is set, control is returned to
+    /// the caller immediately rather than hanging this thread until the remaining data's delay
+    /// elapses; any such data stays queued on the `RelayPath` and is picked up the next time
+    /// this (or the passive relayer) polls it.
+    fn drain_ready_operational_data(&self, cancel: &AtomicBool) -> Result<Vec<IbcEvent>, LinkError> {
+        let mut results = vec![];
+
+        while !cancel.load(Ordering::Relaxed) {
+            let (src_ods, dst_ods) = self.a_to_b.try_fetch_scheduled_operational_data()?;
 
+            if src_ods.is_empty() && dst_ods.is_empty() {
+                break;
+            }
+
+            for od in dst_ods {
+                let mut reply = self.relay_from_operational_data::<SyncSender>(od)?;
                 results.append(&mut reply.events);
             }
 
             for od in src_ods {
-                let mut reply =
-                    self.relay_from_operational_data::<SyncSender>(od.clone())?;
+                let mut reply = self.relay_from_operational_data::<SyncSender>(od)?;
                 results.append(&mut reply.events);
             }
         }
 
-        while let Some(odata) = self.a_to_b.fetch_scheduled_operational_data()? {
-            let mut last_res = self
-                .a_to_b
-                .relay_from_operational_data::<SyncSender>(odata)?;
-            results.append(&mut last_res);
+        Ok(results)
+    }
+
+    /// Splits a batch of `SendPacket` events into those still within their timeout window and
+    /// those whose `timeout_height`/`timeout_timestamp` has already elapsed against the
+    /// destination chain's latest state.
+    ///
+    /// The former are returned unchanged, to be relayed as `MsgRecvPacket`s. The latter are
+    /// re-tagged as `TimeoutPacket` events: fed through the same [`RelayPath::events_to_operational_data`]
+    /// used for the recv set, a `TimeoutPacket` event drives that conversion's existing
+    /// timeout-handling branch -- the same one the passive relayer relies on -- which builds a
+    /// `MsgTimeout`/`MsgTimeoutOnClose` and queues it onto `src_operational_data` so it is
+    /// submitted back to the source chain, rather than being converted into a `MsgRecvPacket`
+    /// that the destination would just reject.
+    fn partition_timed_out_events(
+        &self,
+        events: Vec<IbcEvent>,
+    ) -> Result<(Vec<IbcEvent>, Vec<IbcEvent>), LinkError> {
+        let dst_status = self
+            .a_to_b
+            .dst_chain()
+            .query_application_status()
+            .map_err(LinkError::supervisor)?;
+
+        let mut to_relay = Vec::new();
+        let mut timed_out = Vec::new();
+
+        for event in events {
+            match event {
+                IbcEvent::SendPacket(send)
+                    if packet_has_timed_out(&send.packet, dst_status.height, dst_status.timestamp) =>
+                {
+                    timed_out.push(IbcEvent::TimeoutPacket(TimeoutPacket {
+                        height: dst_status.height,
+                        packet: send.packet,
+                    }));
+                }
+                other => to_relay.push(other),
+            }
         }
 
-        Ok(results)
+        Ok((to_relay, timed_out))
     }
 }
 
+/// Whether `packet`'s timeout has already elapsed against the destination chain's latest
+/// height/timestamp. A zero/disabled `timeout_height` or `timeout_timestamp` never triggers on
+/// its own. The destination rejects a packet once its observed height or time reaches (`>=`)
+/// the packet's timeout, so that boundary is used here too.
+fn packet_has_timed_out(packet: &Packet, dst_height: Height, dst_timestamp: Timestamp) -> bool {
+    (!packet.timeout_height.is_zero() && dst_height >= packet.timeout_height)
+        || (packet.timeout_timestamp.as_nanoseconds() != 0
+            && dst_timestamp >= packet.timeout_timestamp)
+}
+
+/// Waits, in small fixed quanta of [`WAIT_TICK`], for `odata`'s connection delay to elapse,
+/// re-invoking `chain_time`/`latest_height` after each quantum so the estimate self-corrects
+/// if blocks arrive faster or slower than `max_expected_time_per_block` predicted.
+///
+/// Checks `cancel` on every quantum and aborts the wait, returning `Ok(None)`, as soon as it is
+/// set -- e.g. in response to a Ctrl-C or a supervisor-issued stop -- rather than sleeping for
+/// the full remaining delay regardless.
 fn wait_for_conn_delay<ChainTime, MaxBlockTime, LatestHeight>(
     odata: OperationalData,
+    cancel: &AtomicBool,
     chain_time: &ChainTime,
     max_expected_time_per_block: &MaxBlockTime,
     latest_height: &LatestHeight,
-) -> Result<OperationalData, LinkError>
+) -> Result<Option<OperationalData>, LinkError>
 where
     ChainTime: Fn() -> Result<Instant, LinkError>,
     MaxBlockTime: Fn() -> Result<Duration, LinkError>,
     LatestHeight: Fn() -> Result<Height, LinkError>,
 {
-    let (time_left, blocks_left) =
-        odata.conn_delay_remaining(chain_time, max_expected_time_per_block, latest_height)?;
+    let mut ticks: u32 = 0;
+
+    loop {
+        if cancel.load(Ordering::Relaxed) {
+            info!(
+                "wait for scheduled op. data targeting {} cancelled",
+                odata.target,
+            );
+            return Ok(None);
+        }
+
+        let (time_left, blocks_left) =
+            odata.conn_delay_remaining(chain_time, max_expected_time_per_block, latest_height)?;
 
-    match (time_left, blocks_left) {
-        (Duration::ZERO, 0) => {
+        if (time_left, blocks_left) == (Duration::ZERO, 0) {
             info!(
                 "ready to fetch a scheduled op. data with batch of size {} targeting {}",
                 odata.batch.len(),
                 odata.target,
             );
-            Ok(odata)
+            return Ok(Some(odata));
         }
-        (Duration::ZERO, blocks_left) => {
-            info!(
-                    "waiting ({:?} blocks left) for a scheduled op. data with batch of size {} targeting {}",
-                    blocks_left,
-                    odata.batch.len(),
-                    odata.target,
-                );
 
-            let blocks_left: u32 = blocks_left.try_into().expect("blocks_left > u32::MAX");
-
-            // Wait until the delay period passes
-            thread::sleep(blocks_left * max_expected_time_per_block()?);
+        let blocks_left: u32 = blocks_left.try_into().expect("blocks_left > u32::MAX");
+        let estimated_left = if blocks_left > 0 {
+            blocks_left * max_expected_time_per_block()?
+        } else {
+            time_left
+        };
 
-            Ok(odata)
-        }
-        (time_left, _) => {
+        // Logging on every tick would flood the log, since delays are commonly minutes to
+        // hours long: log on the first tick only, then roughly every `WAIT_LOG_EVERY_N_TICKS`.
+        if ticks % WAIT_LOG_EVERY_N_TICKS == 0 {
             info!(
-                "waiting ({:?} left) for a scheduled op. data with batch of size {} targeting {}",
-                time_left,
+                "waiting (~{:?} left) for a scheduled op. data with batch of size {} targeting {}",
+                estimated_left,
                 odata.batch.len(),
                 odata.target,
             );
+        }
+        ticks = ticks.wrapping_add(1);
 
-            // Wait until the delay period passes
-            thread::sleep(time_left);
+        // Wait one tick at a time, rather than sleeping for the full estimate in one shot, so
+        // that `cancel` is noticed promptly and the estimate is re-derived from fresh chain
+        // state on the next iteration.
+        thread::sleep(estimated_left.min(WAIT_TICK));
+    }
+}
 
-            // `blocks_left` maybe non-zero, so recurse to recheck that all delays are handled.
-            wait_for_conn_delay(
-                odata,
-                chain_time,
-                max_expected_time_per_block,
-                latest_height,
-            )
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ibc::core::ics04_channel::packet::Sequence;
+    use ibc::core::ics24_host::identifier::{ChannelId, PortId};
+
+    fn packet_with_timeout(timeout_height: Height, timeout_timestamp: Timestamp) -> Packet {
+        Packet {
+            sequence: Sequence::from(1),
+            source_port: PortId::default(),
+            source_channel: ChannelId::default(),
+            destination_port: PortId::default(),
+            destination_channel: ChannelId::default(),
+            data: Vec::new(),
+            timeout_height,
+            timeout_timestamp,
         }
     }
+
+    #[test]
+    fn not_timed_out_before_timeout_height() {
+        let packet = packet_with_timeout(Height::new(0, 10), Timestamp::none());
+        assert!(!packet_has_timed_out(&packet, Height::new(0, 9), Timestamp::none()));
+    }
+
+    #[test]
+    fn timed_out_at_and_after_timeout_height() {
+        let packet = packet_with_timeout(Height::new(0, 10), Timestamp::none());
+        assert!(packet_has_timed_out(&packet, Height::new(0, 10), Timestamp::none()));
+        assert!(packet_has_timed_out(&packet, Height::new(0, 11), Timestamp::none()));
+    }
+
+    #[test]
+    fn timed_out_at_and_after_timeout_timestamp() {
+        let timeout = Timestamp::from_nanoseconds(100).unwrap();
+        let packet = packet_with_timeout(Height::zero(), timeout);
+
+        assert!(!packet_has_timed_out(
+            &packet,
+            Height::zero(),
+            Timestamp::from_nanoseconds(99).unwrap()
+        ));
+        assert!(packet_has_timed_out(
+            &packet,
+            Height::zero(),
+            Timestamp::from_nanoseconds(100).unwrap()
+        ));
+        assert!(packet_has_timed_out(
+            &packet,
+            Height::zero(),
+            Timestamp::from_nanoseconds(101).unwrap()
+        ));
+    }
+
+    #[test]
+    fn disabled_timeouts_never_expire() {
+        let packet = packet_with_timeout(Height::zero(), Timestamp::none());
+
+        assert!(!packet_has_timed_out(
+            &packet,
+            Height::new(0, 1_000_000),
+            Timestamp::from_nanoseconds(1_000_000).unwrap()
+        ));
+    }
 }